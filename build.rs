@@ -1,59 +1,112 @@
-use chrono::naive::NaiveDate;
+use chrono::{naive::NaiveDate, Local};
+use serde::{de::Error as _, Deserialize, Deserializer};
 use std::{
     borrow::Cow,
+    collections::BTreeMap,
     env,
     error::Error,
     fmt,
     fs::{self, read_dir, File},
     io::{self, BufWriter, Read, Write},
     path::Path,
-    str::FromStr,
+};
+use syntect::{
+    easy::HighlightLines,
+    highlighting::{Theme, ThemeSet},
+    html::{styled_line_to_highlighted_html, IncludeBackground},
+    parsing::SyntaxSet,
 };
 
 type Result<T = ()> = std::result::Result<T, Box<dyn Error>>;
 
+fn parse_naive_date(s: &str) -> Result<NaiveDate> {
+    Ok(NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .map_err(|e| format!("Expected ISO 8601 Date: {}", e))?)
+}
+
+fn deserialize_date<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<NaiveDate, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    parse_naive_date(&s).map_err(D::Error::custom)
+}
+
+fn deserialize_opt_date<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<NaiveDate>, D::Error> {
+    let s: Option<String> = Option::deserialize(deserializer)?;
+    s.map(|s| parse_naive_date(&s).map_err(D::Error::custom)).transpose()
+}
+
+/// `tags` stays a single comma-separated TOML string (e.g. `"rust, web-dev"`)
+/// rather than a native array, to match the front matter authors already
+/// write for it.
+fn deserialize_tags<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Vec<String>, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    Ok(s.split(',')
+        .map(|t| t.trim().to_owned())
+        .filter(|t| !t.is_empty())
+        .collect())
+}
+
+#[derive(Deserialize)]
 struct PostCfg {
     pub author: String,
-    pub published: NaiveDate,
     pub title: String,
+    #[serde(deserialize_with = "deserialize_date")]
+    pub published: NaiveDate,
+    #[serde(default, deserialize_with = "deserialize_opt_date")]
     pub edited: Option<NaiveDate>,
+    #[serde(default, deserialize_with = "deserialize_tags")]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub series: Option<String>,
+    #[serde(default)]
+    pub draft: bool,
 }
 
-fn parse_naive_date(s: &str) -> Result<NaiveDate> {
-    Ok(NaiveDate::parse_from_str(s, "%Y-%m-%d")
-        .map_err(|e| format!("Expected ISO 8601 Date: {}", e))?)
+/// Splits a post file into its `+++`-delimited TOML front matter and the
+/// markdown body that follows, erroring with `filename` attached instead of
+/// panicking on malformed input.
+fn split_front_matter<'a>(content: &'a str, filename: &Path) -> Result<(&'a str, &'a str)> {
+    let rest = content.strip_prefix("+++").ok_or_else(|| {
+        format!(
+            "{}: expected post to start with a `+++` front matter delimiter",
+            filename.display()
+        )
+    })?;
+    let rest = rest.strip_prefix('\n').unwrap_or(rest);
+    let (front_matter, body) = rest.split_once("\n+++").ok_or_else(|| {
+        format!(
+            "{}: missing closing `+++` front matter delimiter",
+            filename.display()
+        )
+    })?;
+    let body = body.strip_prefix('\n').unwrap_or(body);
+    Ok((front_matter, body))
 }
 
-impl FromStr for PostCfg {
-    type Err = Box<dyn Error>;
+fn parse_post_cfg(content: &str, filename: &Path) -> Result<(PostCfg, String)> {
+    let (front_matter, body) = split_front_matter(content, filename)?;
+    let cfg = toml::from_str(front_matter)
+        .map_err(|e| format!("{}: invalid front matter: {}", filename.display(), e))?;
+    Ok((cfg, body.to_string()))
+}
 
-    fn from_str(s: &str) -> Result<Self> {
-        let mut author = None;
-        let mut title = None;
-        let mut published = None;
-        let mut edited = None;
-        for l in s.lines() {
-            let l = l.trim();
-            if l.is_empty() {
-                continue
-            }
-            let (key, value) = l.split_once("=").ok_or("expected key=value pair")?;
-            let (key, value) = (key.trim(), value.trim());
-            match key {
-                "author" => author = Some(value.to_owned()),
-                "title" => title = Some(value.to_owned()),
-                "published" => published = Some(parse_naive_date(value)?),
-                "edited" => edited = Some(parse_naive_date(value)?),
-                _ => {}
-            }
-        }
-        Ok(PostCfg {
-            author: author.ok_or("Expected author")?,
-            title: title.ok_or("Expected title")?,
-            published: published.ok_or("Expected published date")?,
-            edited,
-        })
-    }
+/// A flattened post record collected while walking the tree, kept around after
+/// the `ModuleTree` is built so the feed can be assembled from it. `content`
+/// is the original, un-highlighted Markdown body (not the `highlight_markdown`
+/// output written to `OUT_DIR`), so the feed's `content_text` carries no
+/// generated HTML.
+struct FeedItem {
+    pub filename: String,
+    pub title: String,
+    pub author: String,
+    pub published: NaiveDate,
+    pub edited: Option<NaiveDate>,
+    pub content: String,
 }
 
 struct Post {
@@ -73,18 +126,69 @@ impl fmt::Display for Post {
     }
 }
 
-enum ModuleContent {
-    Post(Post),
+/// Content for a generated `tags`/`series` leaf module: the original
+/// (unslugified) name plus the filenames of its member posts.
+struct Index {
+    pub name: String,
+    pub posts: Vec<String>,
 }
 
-impl fmt::Display for ModuleContent {
+impl fmt::Display for Index {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Self::Post(p) => p.fmt(f),
+        write!(f, "pub const NAME: &str = {:?};", self.name)?;
+        write!(f, "pub const POSTS: &[&str] = &[")?;
+        for post in &self.posts {
+            write!(f, "{:?},", post)?;
         }
+        write!(f, "];")
+    }
+}
+
+enum ModuleContent {
+    Post(Post),
+    Index(Index),
+}
+
+/// Returns `name` as a valid module identifier, escaping it as a raw
+/// identifier (`r#name`) if it collides with a reserved Rust keyword. `self`,
+/// `Self`, `super` and `crate` cannot be raw identifiers, so those get a
+/// trailing `_` instead.
+fn mod_ident(name: &str) -> Cow<'_, str> {
+    const KEYWORDS: &[&str] = &[
+        "as", "break", "const", "continue", "crate", "else", "enum", "extern", "false", "fn",
+        "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref",
+        "return", "self", "Self", "static", "struct", "super", "trait", "true", "type", "unsafe",
+        "use", "where", "while", "async", "await", "dyn", "abstract", "become", "box", "do",
+        "final", "macro", "override", "priv", "typeof", "unsized", "virtual", "yield", "try",
+    ];
+    match name {
+        "self" | "Self" | "super" | "crate" => Cow::Owned(format!("{}_", name)),
+        _ if KEYWORDS.contains(&name) => Cow::Owned(format!("r#{}", name)),
+        _ => Cow::Borrowed(name),
     }
 }
 
+/// Lowercases `s` and maps every non-alphanumeric character to `_`, prefixing
+/// with `_` if the result would otherwise start with a digit or be a lone `_`,
+/// so any non-empty result is usable as a Rust module identifier (keywords
+/// are escaped later by `mod_ident`).
+fn slugify(s: &str) -> String {
+    let mut slug: String = s
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if slug == "_" || slug.chars().next().is_some_and(|c| c.is_ascii_digit()) {
+        slug.insert(0, '_');
+    }
+    slug
+}
+
 struct Root(Vec<ModuleTree>);
 
 #[derive(Default)]
@@ -94,65 +198,380 @@ struct ModuleTree {
     pub content: Option<ModuleContent>,
 }
 
-fn build_module_tree(out_dir: &Path) -> Result<Root> {
-    let mut modules = vec![];
-    let mut posts: Vec<Post> = vec![];
-    let mut path = out_dir.join("posts/");
-    fs::create_dir_all(&path)?;
-    for entry in read_dir("src/posts")? {
+/// Mutable state threaded through `walk` as it recurses, kept in one place so
+/// adding another cross-cutting concern doesn't mean adding another parameter.
+struct WalkState {
+    items: Vec<FeedItem>,
+    tags: BTreeMap<String, Vec<String>>,
+    series: BTreeMap<String, Vec<(NaiveDate, String)>>,
+    syntax_set: SyntaxSet,
+    theme: Theme,
+    include_drafts: bool,
+}
+
+impl WalkState {
+    fn new() -> Result<Self> {
+        let theme_name =
+            env::var("BLOG_HIGHLIGHT_THEME").unwrap_or_else(|_| "InspiredGitHub".to_string());
+        let mut themes = ThemeSet::load_defaults();
+        let theme = themes
+            .themes
+            .remove(&theme_name)
+            .ok_or_else(|| format!("unknown syntax highlighting theme: {}", theme_name))?;
+        Ok(WalkState {
+            items: vec![],
+            tags: BTreeMap::new(),
+            series: BTreeMap::new(),
+            syntax_set: SyntaxSet::load_defaults_newlines(),
+            theme,
+            include_drafts: env::var("BLOG_INCLUDE_DRAFTS").as_deref() == Ok("1"),
+        })
+    }
+}
+
+/// Pre-renders fenced code blocks in `content` to highlighted HTML, leaving
+/// everything outside a fence untouched so the rest of the markdown still
+/// renders normally downstream.
+fn highlight_markdown(content: &str, state: &WalkState) -> String {
+    let mut out = String::with_capacity(content.len());
+    let mut fence: Option<(String, Vec<&str>)> = None;
+    for line in content.lines() {
+        let is_fence_marker = line.trim_start().starts_with("```");
+        match fence {
+            None if is_fence_marker => {
+                let lang = line.trim_start().trim_start_matches('`').trim().to_string();
+                fence = Some((lang, vec![]));
+            }
+            None => {
+                out.push_str(line);
+                out.push('\n');
+            }
+            Some((lang, body)) if is_fence_marker => {
+                let syntax = state
+                    .syntax_set
+                    .find_syntax_by_token(&lang)
+                    .unwrap_or_else(|| state.syntax_set.find_syntax_plain_text());
+                let mut highlighter = HighlightLines::new(syntax, &state.theme);
+                out.push_str("<pre class=\"code\">");
+                for body_line in body {
+                    if let Ok(ranges) = highlighter.highlight_line(body_line, &state.syntax_set) {
+                        if let Ok(html) =
+                            styled_line_to_highlighted_html(&ranges, IncludeBackground::No)
+                        {
+                            out.push_str(&html);
+                        }
+                    }
+                    out.push('\n');
+                }
+                out.push_str("</pre>\n");
+                fence = None;
+            }
+            Some((lang, mut body)) => {
+                body.push(line);
+                fence = Some((lang, body));
+            }
+        }
+    }
+    if let Some((lang, body)) = fence {
+        println!(
+            "cargo:warning=unterminated ```{} fence at end of post; emitting its contents verbatim",
+            lang
+        );
+        for body_line in body {
+            out.push_str(body_line);
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// Recursively walks `src`, mirroring its directory layout into `out` (creating
+/// subdirectories as needed) and into the returned `ModuleTree`. `rel` is the
+/// slash-joined path of `src` relative to the posts root, used as the prefix
+/// for `Post::filename` so nested posts still resolve via `OUT_DIR`.
+fn walk(
+    src: &Path,
+    out: &Path,
+    rel: &str,
+    name: Cow<'static, str>,
+    state: &mut WalkState,
+) -> Result<ModuleTree> {
+    fs::create_dir_all(out)?;
+    let mut tree = ModuleTree {
+        name,
+        ..Default::default()
+    };
+    for entry in read_dir(src)? {
         let entry = entry?;
         let file_name = entry.file_name();
-        let file_name = file_name.to_string_lossy();
+        let file_name = match file_name.to_str() {
+            Some(s) => s,
+            None => {
+                println!("cargo:warning=ignored non-UTF-8 entry: {:?}", entry.path());
+                continue
+            }
+        };
+        if file_name == ".." || file_name.contains('/') {
+            return Err(format!("invalid path component: {}", file_name).into());
+        }
+        if entry.file_type()?.is_dir() {
+            let child_rel = if rel.is_empty() {
+                file_name.to_string()
+            } else {
+                format!("{}/{}", rel, file_name)
+            };
+            tree.children.push(walk(
+                &entry.path(),
+                &out.join(file_name),
+                &child_rel,
+                file_name.to_string().into(),
+                state,
+            )?);
+            continue
+        }
         match file_name.strip_suffix(".md") {
-            Some(id) if entry.file_type()?.is_file() => {
+            Some(id) => {
                 let mut st = String::new();
                 File::open(entry.path())?.read_to_string(&mut st)?;
-                let (cfg, content) = st.split_once("%%").unwrap();
+                let (cfg, content) = parse_post_cfg(&st, &entry.path())?;
                 let PostCfg {
                     author,
                     title,
                     published,
                     edited,
-                } = cfg.parse()?;
-                path.push(file_name.as_ref());
-                let mut file = File::create(&path)?;
+                    tags: post_tags,
+                    series: post_series,
+                    draft,
+                } = cfg;
+                let filename = if rel.is_empty() {
+                    id.to_string()
+                } else {
+                    format!("{}/{}", rel, id)
+                };
+                if !state.include_drafts {
+                    let today = Local::now().date_naive();
+                    if draft {
+                        println!("cargo:warning=skipped {}: marked as draft", filename);
+                        continue
+                    }
+                    if published > today {
+                        println!(
+                            "cargo:warning=skipped {}: scheduled for {}",
+                            filename,
+                            published.format("%Y-%m-%d")
+                        );
+                        continue
+                    }
+                }
+                let highlighted = highlight_markdown(&content, state);
+                let out_path = out.join(file_name);
+                let mut file = File::create(&out_path)?;
                 writeln!(file, "# {}\n", title)?;
                 writeln!(file, "_By {} on {}_\n", author, published.format("%Y-%m-%d"))?;
                 if let Some(edited) = edited {
                     writeln!(file, "Last Edited: {}\n", edited.format("%Y-%m-%d"))?;
                 }
-                file.write_all(content.as_bytes())?;
-                path.pop();
-                posts.push(Post {
-                    filename: id.to_string(),
+                file.write_all(highlighted.as_bytes())?;
+                for tag in &post_tags {
+                    state
+                        .tags
+                        .entry(tag.clone())
+                        .or_default()
+                        .push(filename.clone());
+                }
+                if let Some(series_name) = &post_series {
+                    state
+                        .series
+                        .entry(series_name.clone())
+                        .or_default()
+                        .push((published, filename.clone()));
+                }
+                state.items.push(FeedItem {
+                    filename: filename.clone(),
+                    title: title.clone(),
+                    author,
+                    published,
+                    edited,
+                    content: content.trim().to_string(),
+                });
+                tree.children.push(ModuleTree {
+                    name: id.to_string().into(),
+                    content: Some(ModuleContent::Post(Post { filename })),
+                    ..Default::default()
                 })
             }
-            _ => println!("cargo:warning=ignored: {}", file_name),
+            None => println!("cargo:warning=ignored: {}", file_name),
+        }
+    }
+    Ok(tree)
+}
+
+/// Groups `map`'s entries by `slugify`d key, merging the post lists of any
+/// names that collide once slugified and dropping names that slugify to an
+/// empty identifier (warning via `cargo:warning` in both cases), so the
+/// caller never has to emit two sibling modules with the same name or a
+/// `pub mod {` with no identifier at all.
+fn group_by_slug<T>(
+    map: BTreeMap<String, Vec<T>>,
+    kind: &str,
+) -> BTreeMap<String, (String, Vec<T>)> {
+    let mut grouped: BTreeMap<String, (String, Vec<T>)> = BTreeMap::new();
+    for (name, posts) in map {
+        let slug = slugify(&name);
+        if slug.is_empty() {
+            println!(
+                "cargo:warning={} {:?} slugifies to an empty module name; skipping",
+                kind, name
+            );
+            continue
+        }
+        match grouped.entry(slug) {
+            std::collections::btree_map::Entry::Vacant(e) => {
+                e.insert((name, posts));
+            }
+            std::collections::btree_map::Entry::Occupied(mut e) => {
+                println!(
+                    "cargo:warning={} {:?} and {:?} collide after slugifying; merging into {:?}",
+                    kind,
+                    e.get().0,
+                    name,
+                    e.key()
+                );
+                e.get_mut().1.extend(posts);
+            }
         }
     }
-    let mut posts_mod = ModuleTree {
-        name: "all_posts".into(),
+    grouped
+}
+
+fn build_module_tree(out_dir: &Path) -> Result<(Root, Vec<FeedItem>)> {
+    let mut state = WalkState::new()?;
+    let posts_mod = walk(
+        Path::new("src/posts"),
+        &out_dir.join("posts"),
+        "",
+        "all_posts".into(),
+        &mut state,
+    )?;
+    let WalkState {
+        mut items,
+        tags,
+        series,
+        ..
+    } = state;
+    items.sort_by_key(|item| std::cmp::Reverse(item.published));
+
+    let mut tags_mod = ModuleTree {
+        name: "tags".into(),
         ..Default::default()
     };
-    for post in posts {
-        posts_mod.children.push(ModuleTree {
-            name: post.filename.clone().into(),
-            content: Some(ModuleContent::Post(post)),
+    for (slug, (name, posts)) in group_by_slug(tags, "tag") {
+        tags_mod.children.push(ModuleTree {
+            name: slug.into(),
+            content: Some(ModuleContent::Index(Index { name, posts })),
             ..Default::default()
-        })
+        });
+    }
+
+    let mut series_mod = ModuleTree {
+        name: "series".into(),
+        ..Default::default()
+    };
+    for (slug, (name, mut posts)) in group_by_slug(series, "series") {
+        posts.sort_by_key(|(published, _)| *published);
+        let posts = posts.into_iter().map(|(_, filename)| filename).collect();
+        series_mod.children.push(ModuleTree {
+            name: slug.into(),
+            content: Some(ModuleContent::Index(Index { name, posts })),
+            ..Default::default()
+        });
     }
-    modules.push(posts_mod);
-    Ok(Root(modules))
+
+    Ok((Root(vec![posts_mod, tags_mod, series_mod]), items))
+}
+
+/// Minimal JSON string escaping; the feed only ever contains plain strings, so
+/// this covers quotes, backslashes, and control characters without pulling in
+/// a JSON crate.
+fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// `NaiveDate` posts are assumed to publish at midnight UTC, so RFC 3339
+/// formatting is just appending the zero time and `Z` offset.
+fn rfc3339_midnight_utc(d: NaiveDate) -> String {
+    format!("{}T00:00:00Z", d.format("%Y-%m-%d"))
+}
+
+/// Serializes `items` as a JSON Feed 1.1 document to `dest`. Only
+/// `content_text` is emitted, since `item.content` is plain Markdown and the
+/// feed has no HTML renderer to produce a spec-compliant `content_html`.
+fn print_feed(items: &[FeedItem], home_page_url: &str, site_title: &str, dest: &Path) -> Result {
+    let mut w = BufWriter::new(File::create(dest)?);
+    write!(
+        w,
+        r#"{{"version":"https://jsonfeed.org/version/1.1","title":"{}","home_page_url":"{}","items":["#,
+        json_escape(site_title),
+        json_escape(home_page_url),
+    )?;
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            write!(w, ",")?;
+        }
+        let url = format!("{}/posts/{}", home_page_url.trim_end_matches('/'), item.filename);
+        write!(
+            w,
+            r#"{{"id":"{url}","url":"{url}","title":"{title}","date_published":"{published}""#,
+            url = json_escape(&url),
+            title = json_escape(&item.title),
+            published = rfc3339_midnight_utc(item.published),
+        )?;
+        if let Some(edited) = item.edited {
+            write!(
+                w,
+                r#","date_modified":"{}""#,
+                rfc3339_midnight_utc(edited)
+            )?;
+        }
+        write!(
+            w,
+            r#","author":{{"name":"{author}"}},"content_text":"{content}"}}"#,
+            author = json_escape(&item.author),
+            content = json_escape(&item.content),
+        )?;
+    }
+    write!(w, "]}}")?;
+    w.flush()?;
+    Ok(())
 }
 
 fn print(rt: Root, dest: &Path) -> Result {
     let mut w = BufWriter::new(File::create(dest)?);
     w.write_all(br#"macro_rules! p { ($a:tt) => { concat!(env!("OUT_DIR"), $a) } }"#)?;
+    write!(w, r#"pub const FEED_JSON: &str = include_str!(p!("/feed.json"));"#)?;
     fn print_inner<W: io::Write>(module: ModuleTree, w: &mut W) -> Result {
-        if let Some(content) = module.content {
-            write!(w, "{}", content)?;
+        // `Post` content is a `#[doc = ...]` attribute, which must precede the
+        // `mod` item it documents; `Index` content is ordinary `const` items,
+        // which must instead live inside the module's braces.
+        if let Some(ModuleContent::Post(post)) = &module.content {
+            write!(w, "{}", post)?;
+        }
+        write!(w, "pub mod {}{{", mod_ident(&module.name))?;
+        if let Some(ModuleContent::Index(index)) = &module.content {
+            write!(w, "{}", index)?;
         }
-        write!(w, "pub mod {}{{", module.name)?;
         for child in module.children {
             print_inner(child, w)?;
         }
@@ -168,7 +587,18 @@ fn print(rt: Root, dest: &Path) -> Result {
 
 fn main() -> Result {
     let out_dir = env::var("OUT_DIR")?;
-    let root = build_module_tree(Path::new(&out_dir))?;
+    let (root, items) = build_module_tree(Path::new(&out_dir))?;
+
+    let site_title = env::var("BLOG_TITLE").unwrap_or_else(|_| "fee1-dead's blog".to_string());
+    let home_page_url =
+        env::var("BLOG_HOME_URL").unwrap_or_else(|_| "https://fee1-dead.github.io".to_string());
+    print_feed(
+        &items,
+        &home_page_url,
+        &site_title,
+        &Path::new(&out_dir).join("feed.json"),
+    )?;
+
     let dest_path = Path::new(&out_dir).join("magic.rs");
     print(root, &dest_path)?;
     println!("cargo:rerun-if-changed=src/posts");